@@ -2,18 +2,31 @@ use std::{
     borrow::ToOwned,
     cmp::max,
     collections::{HashMap, HashSet},
-    fs::File,
+    env,
+    error::Error,
+    fs::{self, File},
     io::{stdin, BufRead, BufReader, BufWriter, Read, Write},
     path::{Path, PathBuf},
     process::{exit, Command},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc,
+    },
+    thread,
 };
 
-use image::{self, ImageBuffer, RgbaImage};
+use image::{
+    self,
+    gif::{Delay, Encoder, Frame},
+    ImageBuffer, RgbaImage,
+};
 use mediawiki::{tilesheet::Tilesheet, Csrf, Mediawiki, Token, Upload};
 use regex::Regex;
+use serde_json::json;
+use sha2::{Digest, Sha256};
 use walkdir::WalkDir;
 
-use crate::{decode_srgb, encode_srgb, fix_translucent, resize, FloatImage};
+use crate::{decode_srgb, encode_srgb, fix_translucent, resize};
 
 struct Sheet {
     size: u32,
@@ -40,11 +53,12 @@ impl Sheet {
         }
         *old_layer = new_layer;
     }
-    fn insert(&mut self, TilePos { x, y, z }: TilePos, img: &FloatImage) {
-        let (width, height) = img.dimensions();
-        assert_eq!(width, height);
-        let img = resize(img, self.size, self.size);
-        let img = encode_srgb(&img);
+    /// Blits an already-resized-and-encoded cell into place, growing the layer if needed.
+    ///
+    /// Resizing/encoding happens on worker threads (see `TilesheetManager::update`); this only
+    /// touches shared layer state, so it stays on the thread that owns the `Sheet`.
+    fn place(&mut self, pos @ TilePos { x, y, .. }: TilePos, cell: &RgbaImage) {
+        let z = pos.z;
         if z as usize == self.layers.len() {
             self.add_layer();
         }
@@ -54,11 +68,17 @@ impl Sheet {
             let (nw, nh) = (max((x + 1) * self.size, w), max((y + 1) * self.size, h));
             self.grow(max(w, nw), max(h, nh), z)
         }
-        let (x, y) = (x * self.size, y * self.size);
-        let layer = &mut self.layers[z as usize];
-        for (xx, yy, &pix) in img.enumerate_pixels() {
-            layer.put_pixel(x + xx, y + yy, pix);
-        }
+        blit(&mut self.layers[z as usize], pos, self.size, cell);
+    }
+}
+
+/// Writes a single already-sized cell into a layer at `pos`, assuming the layer is already large
+/// enough. Shared by [`Sheet::place`] and `TilesheetManager::upload_animations`, which re-blits an
+/// animated tile's frames over a clone of the static layer to build each GIF frame.
+fn blit(layer: &mut RgbaImage, TilePos { x, y, .. }: TilePos, size: u32, cell: &RgbaImage) {
+    let (x, y) = (x * size, y * size);
+    for (xx, yy, &pix) in cell.enumerate_pixels() {
+        layer.put_pixel(x + xx, y + yy, pix);
     }
 }
 
@@ -69,58 +89,446 @@ struct Tile {
 }
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
-struct TilePos {
-    x: u32,
-    y: u32,
-    z: u32,
+pub struct TilePos {
+    pub x: u32,
+    pub y: u32,
+    pub z: u32,
+}
+
+/// A tile recorded by a [`TilesheetStore`], as returned by [`TilesheetStore::list_tiles`].
+pub struct StoredTile {
+    pub pos: TilePos,
+    pub name: String,
+    pub id: Option<u64>,
+}
+
+/// A worker's result from `TilesheetManager::update`, sent over its channel to the single writer
+/// thread that owns the `Sheet`s.
+enum TileJob {
+    /// An already-resized-and-encoded cell for a regular (or animated tile's representative)
+    /// frame, ready for [`Sheet::place`].
+    Static(TilePos, u32, RgbaImage),
+    /// An animated tile's full frame sequence at one output size, each paired with its own
+    /// duration in ticks, collected into `TilesheetManager::animations` for `upload_animations`.
+    Animation(TilePos, u32, Vec<(RgbaImage, u32)>),
+}
+
+/// Destination for packed tilesheet layers and tile placement metadata.
+///
+/// `TilesheetManager` only ever talks to a store through this trait, so the same packing and
+/// optimization pipeline can target a wiki, a CDN bucket, or a local mirror just by swapping the
+/// implementor passed to [`update_tilesheet_with_store`].
+pub trait TilesheetStore {
+    /// Returns the layer sizes of an existing tilesheet, or `None` if it hasn't been created yet.
+    fn sheet_sizes(&self, name: &str) -> Option<Vec<u32>>;
+    /// Creates a brand new tilesheet with the given layer sizes.
+    fn create_sheet(&self, name: &str, sizes: &[u32]) -> Result<(), Box<dyn Error>>;
+    /// Loads a previously stored layer's PNG bytes, or `None` if that layer doesn't exist yet.
+    fn load_layer(&self, name: &str, size: u32, z: u32) -> Option<Vec<u8>>;
+    /// Persists an already-encoded PNG layer.
+    fn put_layer(&self, name: &str, size: u32, z: u32, png: &[u8]) -> Result<(), Box<dyn Error>>;
+    /// Persists an animated artifact (e.g. an animated GIF) for a layer that contains animated
+    /// tiles, stored alongside the static layer under a parallel `... anim.<ext>` name.
+    fn put_animation(&self, name: &str, size: u32, z: u32, ext: &str, data: &[u8]) -> Result<(), Box<dyn Error>>;
+    /// Lists every tile currently recorded for a tilesheet.
+    fn list_tiles(&self, name: &str) -> Vec<StoredTile>;
+    /// Records newly placed tiles.
+    fn put_tile_metadata(&self, name: &str, tiles: &[(TilePos, String)]) -> Result<(), Box<dyn Error>>;
+    /// Removes tiles by id, scoped to a single tilesheet (a store may assign ids that are only
+    /// unique within one sheet, as `LocalDirStore` does).
+    fn delete_tiles(&self, name: &str, ids: &[u64]) -> Result<(), Box<dyn Error>>;
 }
 
-struct TilesheetManager {
+/// Stores tilesheets on the FTB wiki, via the MediaWiki API.
+struct MediaWikiStore {
     mw: Mediawiki,
+}
+
+impl MediaWikiStore {
+    fn login_path(path: &str) -> MediaWikiStore {
+        MediaWikiStore { mw: Mediawiki::login_path(path).unwrap() }
+    }
+    fn upload_tilesheet(&self, filename: &str, file: Upload, token: &Token<Csrf>, ignorewarnings: bool) {
+        // If we are ignoring warnings, we already attempted an upload so don't print anything.
+        if !ignorewarnings {
+            println!("Uploading \"{}\"", filename);
+        }
+        let text = "[[Category:Tilesheets]]";
+        let comment = "Uploaded tilesheet using ftb-rs";
+        let result = self.mw.upload(filename, &token, file, Some(text), Some(comment), ignorewarnings);
+
+        if let Ok(v) = result {
+            if v.get("errors").is_none() {
+                let upload = v.get("upload").unwrap();
+                let response = upload.get("result").unwrap().as_str().unwrap();
+                let filekey = &upload["filekey"].as_str();
+                match response {
+                    "Warning" => {
+                        let warnings = &upload["warnings"];
+                        let map = warnings.as_object().unwrap();
+                        let reupload = map.get("exists").and_then(|v| v.as_str()).map(|s| s == filename.replace(" ", "_")).unwrap_or(false);
+                        if map.len() == 1 && reupload {
+                            // Warning is about the page already existing, but we are updating it.
+                            self.upload_tilesheet(filename, Upload::Filekey(filekey.unwrap()), token, true);
+                            return;
+                        }
+                        println!("The API returned warnings when attempting to upload the file.");
+                        println!("Warnings: {}", serde_json::to_string(warnings).unwrap());
+                        println!("Would you like to try to upload the file again and ignore these warnings? y/n");
+                        let mut input = String::new();
+                        stdin().read_line(&mut input).unwrap();
+                        input = input.trim().to_owned();
+                        if input.to_ascii_lowercase() == "y" {
+                            self.upload_tilesheet(filename, Upload::Filekey(filekey.unwrap()), token, true);
+                        } else {
+                            println!("Please manually upload {}", filename);
+                        }
+                    }
+                    "Success" => {
+                        println!("Successfully uploaded {}", filename);
+                    }
+                    other => panic!("Unknown result: {}", other),
+                }
+            } else {
+                println!("An error occurred when uploading \"{}\". Please manually upload the file.", filename);
+                let errors = v.get("errors").unwrap().as_array();
+                if let Some(vec) = errors {
+                    let mut count = 1;
+                    for error in vec {
+                        let code = error["code"].as_str().unwrap_or("");
+                        let description = error["*"].as_str().unwrap_or("");
+                        println!("Error response from API ({}): {} - {}", count, code, description);
+                        count += 1;
+                    }
+                } else {
+                    println!("The API didn't return any error objects to display.");
+                }
+            }
+        } else {
+            println!("An error occurred when uploading \"{}\". Please manually upload the file.", filename);
+            println!("Error locally: {:?}", result);
+        }
+    }
+}
+
+impl TilesheetStore for MediaWikiStore {
+    fn sheet_sizes(&self, name: &str) -> Option<Vec<u32>> {
+        let sheet = self
+            .mw
+            .query_sheets()
+            .into_iter()
+            .find(|x| x.as_ref().ok().and_then(|x| x.get("mod")).and_then(|x| x.as_str()).map_or(false, |x| x == name));
+        match sheet {
+            Some(Ok(sheet)) => Some(sheet["sizes"].as_array().unwrap().iter().map(|x| x.as_u64().unwrap() as u32).collect()),
+            _ => None,
+        }
+    }
+    fn create_sheet(&self, name: &str, sizes: &[u32]) -> Result<(), Box<dyn Error>> {
+        let token = self.mw.get_token()?;
+        let sizes = sizes.iter().map(u32::to_string).collect::<Vec<_>>().join("|");
+        self.mw.create_sheet(&token, name, &sizes)?;
+        Ok(())
+    }
+    fn load_layer(&self, name: &str, size: u32, z: u32) -> Option<Vec<u8>> {
+        self.mw.download_file(&format!("Tilesheet {} {} {}.png", name, size, z)).unwrap()
+    }
+    fn put_layer(&self, name: &str, size: u32, z: u32, png: &[u8]) -> Result<(), Box<dyn Error>> {
+        let filename = format!("Tilesheet {} {} {}.png", name, size, z);
+        let path = Path::new(r"work/tilesheets").join(&filename);
+        fs::write(&path, png)?;
+        let token = self.mw.get_token()?;
+        self.upload_tilesheet(&filename, Upload::File(path.as_path()), &token, false);
+        Ok(())
+    }
+    fn put_animation(&self, name: &str, size: u32, z: u32, ext: &str, data: &[u8]) -> Result<(), Box<dyn Error>> {
+        let filename = format!("Tilesheet {} {} {} anim.{}", name, size, z, ext);
+        let path = Path::new(r"work/tilesheets").join(&filename);
+        fs::write(&path, data)?;
+        let token = self.mw.get_token()?;
+        self.upload_tilesheet(&filename, Upload::File(path.as_path()), &token, false);
+        Ok(())
+    }
+    fn list_tiles(&self, name: &str) -> Vec<StoredTile> {
+        self.mw
+            .query_tiles(Some(name))
+            .filter_map(|tile| match tile {
+                Ok(tile) => {
+                    let x = tile["x"].as_u64().unwrap() as u32;
+                    let y = tile["y"].as_u64().unwrap() as u32;
+                    let z = tile["z"].as_u64().unwrap() as u32;
+                    let id = tile["id"].as_u64().unwrap();
+                    let name = tile["name"].as_str().unwrap().to_owned();
+                    Some(StoredTile { pos: TilePos { x, y, z }, name, id: Some(id) })
+                }
+                Err(e) => {
+                    println!("WARNING: Error while querying tiles {:?}", e);
+                    None
+                }
+            })
+            .collect()
+    }
+    fn put_tile_metadata(&self, name: &str, tiles: &[(TilePos, String)]) -> Result<(), Box<dyn Error>> {
+        let token = self.mw.get_token()?;
+        for chunk in tiles.chunks(50) {
+            let tiles = chunk.iter().map(|(pos, name)| format!("{} {} {} {}", pos.x, pos.y, pos.z, name)).collect::<Vec<_>>().join("|");
+            if let Err(e) = self.mw.add_tiles(&token, name, &tiles) {
+                println!("ERROR: {:?}", e);
+            }
+        }
+        Ok(())
+    }
+    fn delete_tiles(&self, _name: &str, ids: &[u64]) -> Result<(), Box<dyn Error>> {
+        // The wiki assigns tile ids globally, so there's no need to scope by sheet name here.
+        let token = self.mw.get_token()?;
+        for chunk in ids.chunks(50) {
+            let tiles = chunk.iter().map(|id| id.to_string()).collect::<Vec<_>>().join("|");
+            if let Err(e) = self.mw.delete_tiles(&token, &tiles) {
+                println!("ERROR: {:?}", e);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Stores tilesheets as plain files under a directory, e.g. for mirroring to a CDN bucket mount.
+///
+/// Layers are written to `<root>/<name>/<size>/<z>.png` and tile placement is tracked in a
+/// `<root>/<name>/tiles.json` manifest, since there's no wiki page to hold that metadata.
+pub struct LocalDirStore {
+    root: PathBuf,
+}
+
+impl LocalDirStore {
+    pub fn new(root: impl Into<PathBuf>) -> LocalDirStore {
+        LocalDirStore { root: root.into() }
+    }
+    fn sheet_dir(&self, name: &str) -> PathBuf {
+        self.root.join(name)
+    }
+    fn sizes_path(&self, name: &str) -> PathBuf {
+        self.sheet_dir(name).join("sizes.json")
+    }
+    fn tiles_path(&self, name: &str) -> PathBuf {
+        self.sheet_dir(name).join("tiles.json")
+    }
+    fn layer_path(&self, name: &str, size: u32, z: u32) -> PathBuf {
+        self.sheet_dir(name).join(size.to_string()).join(format!("{}.png", z))
+    }
+    fn animation_path(&self, name: &str, size: u32, z: u32, ext: &str) -> PathBuf {
+        self.sheet_dir(name).join(size.to_string()).join(format!("{} anim.{}", z, ext))
+    }
+    fn read_tiles(&self, name: &str) -> Vec<StoredTile> {
+        let data = match fs::read_to_string(self.tiles_path(name)) {
+            Ok(data) => data,
+            Err(_) => return Vec::new(),
+        };
+        let tiles: serde_json::Value = serde_json::from_str(&data).unwrap();
+        tiles
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|tile| StoredTile {
+                pos: TilePos {
+                    x: tile["x"].as_u64().unwrap() as u32,
+                    y: tile["y"].as_u64().unwrap() as u32,
+                    z: tile["z"].as_u64().unwrap() as u32,
+                },
+                name: tile["name"].as_str().unwrap().to_owned(),
+                id: tile["id"].as_u64(),
+            })
+            .collect()
+    }
+    fn write_tiles(&self, name: &str, tiles: &[StoredTile]) -> Result<(), Box<dyn Error>> {
+        let value: Vec<_> = tiles.iter().map(|tile| json!({ "x": tile.pos.x, "y": tile.pos.y, "z": tile.pos.z, "name": tile.name, "id": tile.id })).collect();
+        fs::create_dir_all(self.sheet_dir(name))?;
+        fs::write(self.tiles_path(name), serde_json::to_string_pretty(&value)?)?;
+        Ok(())
+    }
+}
+
+impl TilesheetStore for LocalDirStore {
+    fn sheet_sizes(&self, name: &str) -> Option<Vec<u32>> {
+        let data = fs::read_to_string(self.sizes_path(name)).ok()?;
+        let sizes: Vec<u32> = serde_json::from_str(&data).unwrap();
+        Some(sizes)
+    }
+    fn create_sheet(&self, name: &str, sizes: &[u32]) -> Result<(), Box<dyn Error>> {
+        fs::create_dir_all(self.sheet_dir(name))?;
+        fs::write(self.sizes_path(name), serde_json::to_string_pretty(sizes)?)?;
+        Ok(())
+    }
+    fn load_layer(&self, name: &str, size: u32, z: u32) -> Option<Vec<u8>> {
+        fs::read(self.layer_path(name, size, z)).ok()
+    }
+    fn put_layer(&self, name: &str, size: u32, z: u32, png: &[u8]) -> Result<(), Box<dyn Error>> {
+        let path = self.layer_path(name, size, z);
+        fs::create_dir_all(path.parent().unwrap())?;
+        fs::write(path, png)?;
+        Ok(())
+    }
+    fn put_animation(&self, name: &str, size: u32, z: u32, ext: &str, data: &[u8]) -> Result<(), Box<dyn Error>> {
+        let path = self.animation_path(name, size, z, ext);
+        fs::create_dir_all(path.parent().unwrap())?;
+        fs::write(path, data)?;
+        Ok(())
+    }
+    fn list_tiles(&self, name: &str) -> Vec<StoredTile> {
+        self.read_tiles(name)
+    }
+    fn put_tile_metadata(&self, name: &str, tiles: &[(TilePos, String)]) -> Result<(), Box<dyn Error>> {
+        let mut existing = self.read_tiles(name);
+        let mut next_id = existing.iter().filter_map(|tile| tile.id).max().map_or(0, |id| id + 1);
+        for (pos, tile_name) in tiles {
+            existing.push(StoredTile { pos: *pos, name: tile_name.clone(), id: Some(next_id) });
+            next_id += 1;
+        }
+        self.write_tiles(name, &existing)
+    }
+    fn delete_tiles(&self, name: &str, ids: &[u64]) -> Result<(), Box<dyn Error>> {
+        // Ids are only assigned uniquely within a sheet's own tiles.json, so deletion must stay
+        // scoped to that one sheet; otherwise a colliding id from an unrelated sheet (every sheet
+        // starts numbering at 0) would get deleted too.
+        let mut tiles = self.read_tiles(name);
+        let before = tiles.len();
+        tiles.retain(|tile| tile.id.map_or(true, |id| !ids.contains(&id)));
+        if tiles.len() != before {
+            self.write_tiles(name, &tiles)?;
+        }
+        Ok(())
+    }
+}
+
+struct TilesheetManager<S: TilesheetStore> {
+    store: S,
     name: String,
     tiles: HashMap<String, Tile>,
     entries: HashMap<TilePos, String>,
     renames: HashMap<String, String>,
     added: Vec<String>,
+    added_paths: HashMap<String, PathBuf>,
     missing: HashSet<String>,
     deleted: Vec<u64>,
     tilesheets: Vec<Sheet>,
-    paths: Vec<PathBuf>,
+    paths: Vec<(u32, u32, PathBuf)>,
     next: (u32, u32, u32),
+    force: bool,
+    manifest: HashMap<String, String>,
+    pending_hashes: HashMap<String, String>,
+    dup_threshold: u32,
+    jobs: usize,
+    /// Animated tiles that were packed into a sheet, keyed by layer size: the representative
+    /// frame already went into the static `Sheet` layer via `place`, but the full
+    /// `(frame, duration in ticks)` sequence is kept here so `upload_animations` can assemble a
+    /// parallel animated artifact per layer.
+    animations: HashMap<u32, Vec<(TilePos, Vec<(RgbaImage, u32)>)>>,
 }
 
-impl TilesheetManager {
-    fn new(name: &str) -> TilesheetManager {
+impl<S: TilesheetStore> TilesheetManager<S> {
+    fn new(name: &str, store: S, force: bool, dup_threshold: u32, jobs: usize) -> TilesheetManager<S> {
         println!("Starting up tilesheet manager.");
         TilesheetManager {
-            mw: Mediawiki::login_path("ftb.json").unwrap(),
+            store,
             name: name.to_owned(),
             tiles: HashMap::new(),
             entries: HashMap::new(),
             renames: load_renames(name),
             added: Vec::new(),
+            added_paths: HashMap::new(),
             missing: HashSet::new(),
             deleted: Vec::new(),
             tilesheets: Vec::new(),
             paths: Vec::new(),
             next: (0, 0, 0),
+            force,
+            manifest: if force { HashMap::new() } else { load_manifest(name) },
+            pending_hashes: HashMap::new(),
+            dup_threshold,
+            jobs,
+            animations: HashMap::new(),
+        }
+    }
+    /// Finds visually-identical source tiles via a difference-hash and aliases the duplicates
+    /// onto a single canonical name, reusing the manual `renames.txt` mechanism so they resolve
+    /// to the same cell. Groupings are written to `duplicates.txt` for the user to confirm.
+    fn dedup_duplicates(&mut self) {
+        println!("Checking for duplicate tiles.");
+        let dir = Path::new(r"work/tilesheets").join(&self.name);
+        let mut sources = Vec::new();
+        for entry in WalkDir::new(&dir) {
+            let entry = entry.unwrap();
+            let path = entry.path();
+            if !path.is_file() || path.extension().and_then(|x| x.to_str()) != Some("png") {
+                continue;
+            }
+            let raw_stem = path.file_stem().unwrap().to_str().unwrap().to_owned();
+            let resolved_name = match self.renames.get(&raw_stem) {
+                Some(target) => {
+                    if target.is_empty() {
+                        continue;
+                    }
+                    target.clone()
+                }
+                None => raw_stem.clone(),
+            };
+            // `.mcmeta` animation strips (chunk0-6) are tall multi-frame PNGs; dHash-ing the whole
+            // strip squishes every frame together and produces a hash unrelated to any single
+            // frame, so hash the same representative frame `update()` picks for the static tile.
+            let img = image::open(path).unwrap().to_rgba();
+            let (width, height) = img.dimensions();
+            let representative = if width == height {
+                img
+            } else {
+                let raw_frames = split_frames(&img);
+                let mcmeta = load_mcmeta(path);
+                let order = mcmeta_frame_order(mcmeta.as_ref(), raw_frames.len() as u32);
+                let (representative_index, _) = order[DEFAULT_ANIM_FRAME as usize % order.len()];
+                raw_frames[representative_index as usize].clone()
+            };
+            let hash = dhash(&representative);
+            sources.push((raw_stem, resolved_name, hash));
+        }
+        // WalkDir doesn't guarantee a stable traversal order, so without sorting first, which
+        // tile in a duplicate group gets picked as canonical (`group[0]` below) could change
+        // between runs on the same unchanged source tree, causing spurious renames.
+        sources.sort_by(|a, b| a.1.cmp(&b.1));
+
+        let mut groups: Vec<Vec<usize>> = Vec::new();
+        'sources: for i in 0..sources.len() {
+            for group in &mut groups {
+                // Pairwise, not just against the canonical `group[0]`: otherwise two tiles that
+                // are each within threshold of a common third tile, but not of each other, get
+                // merged onto the same canonical name.
+                if group.iter().all(|&j| hamming_distance(sources[i].2, sources[j].2) <= self.dup_threshold) {
+                    group.push(i);
+                    continue 'sources;
+                }
+            }
+            groups.push(vec![i]);
+        }
+
+        let mut duplicates = BufWriter::new(File::create(r"work/tilesheets/duplicates.txt").unwrap());
+        for group in &groups {
+            if group.len() < 2 {
+                continue;
+            }
+            let (_, canonical_name, _) = &sources[group[0]];
+            let dup_names: Vec<&str> = group[1..].iter().map(|&i| sources[i].1.as_str()).collect();
+            writeln!(duplicates, "{} = {}", canonical_name, dup_names.join(", ")).unwrap();
+            for &i in &group[1..] {
+                let raw_stem = &sources[i].0;
+                self.renames.entry(raw_stem.clone()).or_insert_with(|| canonical_name.clone());
+            }
         }
     }
     fn import_tilesheets(&mut self) {
         println!("Checking for existing tilesheet.");
-        let sheet = self
-            .mw
-            .query_sheets()
-            .into_iter()
-            .find(|x| x.as_ref().ok().and_then(|x| x.get("mod")).and_then(|x| x.as_str()).map_or(false, |x| x == self.name));
-        if let Some(Ok(sheet)) = sheet {
-            let sizes: Vec<u64> = sheet["sizes"].as_array().unwrap().iter().map(|x| x.as_u64().unwrap()).collect();
+        if let Some(sizes) = self.store.sheet_sizes(&self.name) {
             println!("Existing tilesheet sizes: {:?}", sizes);
             println!("Importing existing tilesheet images.");
             for size in sizes {
-                let mut sheet = Sheet::new(size as u32);
+                let mut sheet = Sheet::new(size);
                 for z in 0.. {
-                    if let Some(data) = self.mw.download_file(&format!("Tilesheet {} {} {}.png", self.name, size, z)).unwrap() {
+                    if let Some(data) = self.store.load_layer(&self.name, size, z) {
                         sheet.load_layer(&data);
                     } else {
                         if z == 0 {
@@ -135,33 +543,19 @@ impl TilesheetManager {
             println!("No tilesheet found. Please specify desired sizes separated by commas:");
             let mut sizes = String::new();
             stdin().read_line(&mut sizes).unwrap();
-            let sizes = sizes.split(',').map(str::trim).collect::<Vec<_>>();
-            for size in &sizes {
-                self.tilesheets.push(Sheet::new(size.parse().unwrap()));
+            let sizes: Vec<u32> = sizes.split(',').map(str::trim).map(|s| s.parse().unwrap()).collect();
+            for &size in &sizes {
+                self.tilesheets.push(Sheet::new(size));
             }
-            let token = self.mw.get_token().unwrap();
-            self.mw.create_sheet(&token, &self.name, &sizes.join("|")).unwrap();
+            self.store.create_sheet(&self.name, &sizes).unwrap();
         }
     }
     fn import_tiles(&mut self) {
         println!("Importing tiles.");
-        for tile in self.mw.query_tiles(Some(&*self.name)) {
-            let tile = match tile {
-                Ok(tile) => tile,
-                Err(e) => {
-                    println!("WARNING: Error while querying tiles {:?}", e);
-                    continue;
-                }
-            };
-            let x = tile["x"].as_u64().unwrap() as u32;
-            let y = tile["y"].as_u64().unwrap() as u32;
-            let z = tile["z"].as_u64().unwrap() as u32;
-            let id = tile["id"].as_u64().unwrap();
-            let name = tile["name"].as_str().unwrap();
-            let pos = TilePos { x, y, z };
-            self.tiles.insert(name.to_owned(), Tile { pos, id: Some(id) });
-            self.entries.insert(pos, name.to_owned());
-            self.missing.insert(name.to_owned());
+        for tile in self.store.list_tiles(&self.name) {
+            self.tiles.insert(tile.name.clone(), Tile { pos: tile.pos, id: tile.id });
+            self.entries.insert(tile.pos, tile.name.clone());
+            self.missing.insert(tile.name);
         }
     }
     fn check_changes(&mut self) {
@@ -192,6 +586,7 @@ impl TilesheetManager {
             }
             self.missing.remove(&name);
             if !self.tiles.contains_key(&name) {
+                self.added_paths.insert(name.clone(), path.to_owned());
                 self.added.push(name);
             }
         }
@@ -208,7 +603,9 @@ impl TilesheetManager {
         }
         drop(additions);
         drop(missing);
+        self.preview_added_tiles();
         println!("Please confirm that the tiles being added in additions.txt are correct.");
+        println!("Please also check over the detected duplicates in duplicates.txt, in case any of them are renames.txt candidates that were misdetected.");
         println!("Also please check over the tiles in missing.txt and ensure that not updating them was intentional.");
         println!("If there are tiles in missing.txt that you no longer wish to keep, please copy them to todelete.txt.");
         println!("If you need to make any changes to the tiles or renames.txt please restart this program.");
@@ -220,6 +617,61 @@ impl TilesheetManager {
             exit(1);
         }
     }
+    /// Renders thumbnails of `self.added` directly in the terminal, if it understands an inline
+    /// image protocol, so a maintainer can spot a wrong or corrupt texture before confirming.
+    fn preview_added_tiles(&self) {
+        if self.added.is_empty() {
+            return;
+        }
+        let protocol = detect_graphics_protocol();
+        if protocol == GraphicsProtocol::None {
+            println!("(Terminal does not support Kitty/iTerm2/Sixel inline images; see additions.txt for the full list.)");
+            return;
+        }
+        println!("Preview of added tiles:");
+        let (cell_w, _) = terminal_cell_pixels().unwrap_or((10, 20));
+        let columns = terminal_columns().unwrap_or(80);
+        const THUMB_CELLS: u32 = 8;
+        let thumb_px = THUMB_CELLS * cell_w as u32;
+        let per_row = max(1, columns as u32 / THUMB_CELLS) as usize;
+        for chunk in self.added.chunks(per_row) {
+            for name in chunk {
+                let path = match self.added_paths.get(name) {
+                    Some(path) => path,
+                    None => continue,
+                };
+                let img = match image::open(path) {
+                    Ok(img) => img.to_rgba(),
+                    Err(_) => continue,
+                };
+                let (width, height) = img.dimensions();
+                // An `.mcmeta` animation strip (chunk0-6) is a tall multi-frame PNG; thumbnailing
+                // it directly squishes every frame together, so preview its representative frame
+                // instead, the same one `update()` picks for the static tile.
+                let img = if width == height {
+                    img
+                } else {
+                    let raw_frames = split_frames(&img);
+                    let mcmeta = load_mcmeta(path);
+                    let order = mcmeta_frame_order(mcmeta.as_ref(), raw_frames.len() as u32);
+                    let (representative_index, _) = order[DEFAULT_ANIM_FRAME as usize % order.len()];
+                    raw_frames[representative_index as usize].clone()
+                };
+                let thumb = image::imageops::thumbnail(&img, thumb_px, thumb_px);
+                match protocol {
+                    GraphicsProtocol::Kitty => print!("{}", encode_kitty(&thumb)),
+                    GraphicsProtocol::Iterm2 => print!("{}", encode_iterm2(&thumb)),
+                    GraphicsProtocol::Sixel => print!("{}", encode_sixel(&thumb)),
+                    GraphicsProtocol::None => unreachable!(),
+                }
+            }
+            println!();
+            for name in chunk {
+                print!("{:<width$.width$}", name, width = THUMB_CELLS as usize);
+            }
+            println!();
+        }
+    }
     fn record_deletions(&mut self) {
         let todelete = BufReader::new(File::open(r"work/tilesheets/todelete.txt").unwrap());
         for line in todelete.lines() {
@@ -270,6 +722,10 @@ impl TilesheetManager {
     fn update(&mut self) {
         println!("Updating tilesheet with new tiles.");
         let path = Path::new(r"work/tilesheets").join(&self.name);
+        // Position assignment must stay deterministic, so `lookup` runs here on the main thread
+        // while we walk the directory; only the expensive decode/resize/encode work is handed
+        // off to the worker pool below.
+        let mut jobs = Vec::new();
         for entry in WalkDir::new(&path) {
             let entry = entry.unwrap();
             let path = entry.path();
@@ -293,132 +749,430 @@ impl TilesheetManager {
                 println!("ERROR: Illegal name: {:?}", name);
                 exit(1);
             }
-            let mut img = image::open(&path).unwrap().to_rgba();
-            fix_translucent(&mut img);
-            let img = decode_srgb(&img);
             let pos = self.lookup(&name);
-            for tilesheet in &mut self.tilesheets {
-                tilesheet.insert(pos, &img);
-            }
+            jobs.push((path.to_owned(), pos));
         }
+
+        let total = jobs.len();
+        println!("Packing {} tiles across {} worker threads.", total, self.jobs);
+        let sizes: Vec<u32> = self.tilesheets.iter().map(|tilesheet| tilesheet.size).collect();
+        let next_job = AtomicUsize::new(0);
+        let completed = AtomicUsize::new(0);
+        let (tx, rx) = mpsc::channel();
+        thread::scope(|scope| {
+            for _ in 0..self.jobs.max(1) {
+                let jobs = &jobs;
+                let sizes = &sizes;
+                let next_job = &next_job;
+                let completed = &completed;
+                let tx = tx.clone();
+                scope.spawn(move || loop {
+                    let i = next_job.fetch_add(1, Ordering::SeqCst);
+                    let (path, pos) = match jobs.get(i) {
+                        Some(job) => job,
+                        None => break,
+                    };
+                    let mut img = image::open(path).unwrap().to_rgba();
+                    fix_translucent(&mut img);
+                    let img = decode_srgb(&img);
+                    let (width, height) = img.dimensions();
+                    if width == height {
+                        for &size in sizes {
+                            let cell = encode_srgb(&resize(&img, size, size));
+                            tx.send(TileJob::Static(*pos, size, cell)).unwrap();
+                        }
+                    } else {
+                        assert_eq!(height % width, 0, "Tile {:?} is neither square nor an animation strip (height not a multiple of width)", path);
+                        let raw_frames = split_frames(&img);
+                        let mcmeta = load_mcmeta(path);
+                        // Always non-empty with indices in bounds for `raw_frames`, even if the
+                        // mcmeta is malformed or references a frame the strip no longer has.
+                        let order = mcmeta_frame_order(mcmeta.as_ref(), raw_frames.len() as u32);
+                        let (representative_index, _) = order[DEFAULT_ANIM_FRAME as usize % order.len()];
+                        for &size in sizes {
+                            let representative = encode_srgb(&resize(&raw_frames[representative_index as usize], size, size));
+                            tx.send(TileJob::Static(*pos, size, representative)).unwrap();
+                            let frames = order
+                                .iter()
+                                .map(|&(index, time)| (encode_srgb(&resize(&raw_frames[index as usize], size, size)), time))
+                                .collect();
+                            tx.send(TileJob::Animation(*pos, size, frames)).unwrap();
+                        }
+                    }
+                    let done = completed.fetch_add(1, Ordering::SeqCst) + 1;
+                    if done % 10 == 0 || done == total {
+                        println!("Packed {}/{} tiles", done, total);
+                    }
+                });
+            }
+            drop(tx);
+            for job in rx {
+                match job {
+                    TileJob::Static(pos, size, cell) => {
+                        let tilesheet = self.tilesheets.iter_mut().find(|tilesheet| tilesheet.size == size).unwrap();
+                        tilesheet.place(pos, &cell);
+                    }
+                    TileJob::Animation(pos, size, frames) => {
+                        self.animations.entry(size).or_insert_with(Vec::new).push((pos, frames));
+                    }
+                }
+            }
+        });
     }
     fn optimize(&mut self) {
         println!("Optimizing tilesheets");
-        let paths: Vec<_> = self
+        let changed: Vec<_> = self
             .tilesheets
             .iter()
             .flat_map(|tilesheet| {
                 let name = &self.name[..];
-                tilesheet.layers.iter().enumerate().map(move |(z, layer)| {
-                    let name = format!("Tilesheet {} {} {}.png", name, tilesheet.size, z);
-                    let path = Path::new(r"work/tilesheets").join(name);
+                let manifest = &self.manifest;
+                let force = self.force;
+                tilesheet.layers.iter().enumerate().filter_map(move |(z, layer)| {
+                    let z = z as u32;
+                    let key = manifest_key(tilesheet.size, z);
+                    let hash = format!("{:x}", Sha256::digest(layer.as_raw()));
+                    if !force && manifest.get(&key) == Some(&hash) {
+                        println!("Skipping unchanged layer {} {} {}", name, tilesheet.size, z);
+                        return None;
+                    }
+                    let filename = format!("Tilesheet {} {} {}.png", name, tilesheet.size, z);
+                    let path = Path::new(r"work/tilesheets").join(filename);
                     layer.save(&path).unwrap();
-                    // &self.paths.push(path.to_owned());
-                    path
+                    Some((tilesheet.size, z, path, key, hash))
                 })
             })
             .collect();
-        self.paths.extend(paths);
-        for path in &self.paths {
-            Command::new("optipng").arg(path).spawn().unwrap().wait().unwrap();
-        }
-    }
-    fn upload_sheets(&self) {
-        let token = self.mw.get_token().unwrap();
-        for path in &self.paths {
-            let filename = path.file_name().unwrap().to_str().unwrap();
-            self.upload_tilesheet(filename, Upload::File(path.as_path()), &token, false);
+        for (size, z, path, key, hash) in &changed {
+            self.paths.push((*size, *z, path.clone()));
+            self.pending_hashes.insert(key.clone(), hash.clone());
         }
+
+        let total = changed.len();
+        println!("Running optipng on {} layers across {} worker threads.", total, self.jobs);
+        let next_job = AtomicUsize::new(0);
+        let completed = AtomicUsize::new(0);
+        let paths: Vec<&Path> = changed.iter().map(|(_, _, path, _, _)| path.as_path()).collect();
+        thread::scope(|scope| {
+            for _ in 0..self.jobs.max(1) {
+                let paths = &paths;
+                let next_job = &next_job;
+                let completed = &completed;
+                scope.spawn(move || loop {
+                    let i = next_job.fetch_add(1, Ordering::SeqCst);
+                    let path = match paths.get(i) {
+                        Some(path) => path,
+                        None => break,
+                    };
+                    Command::new("optipng").arg(path).spawn().unwrap().wait().unwrap();
+                    let done = completed.fetch_add(1, Ordering::SeqCst) + 1;
+                    if done % 10 == 0 || done == total {
+                        println!("Optimized {}/{} layers", done, total);
+                    }
+                });
+            }
+        });
     }
-    fn upload_tilesheet(&self, filename: &str, file: Upload, token: &Token<Csrf>, ignorewarnings: bool) {
-        // If we are ignoring warnings, we already attempted an upload so don't print anything.
-        if !ignorewarnings {
-            println!("Uploading \"{}\"", filename);
+    /// Assembles an animated GIF per layer that contains animated tiles, replaying each animated
+    /// tile's frame sequence over a clone of that layer's already-packed static image (so
+    /// non-animated tiles just hold their one frame), and uploads it alongside the static layer.
+    fn upload_animations(&mut self) {
+        if self.animations.is_empty() {
+            return;
         }
-        let text = "[[Category:Tilesheets]]";
-        let comment = "Uploaded tilesheet using ftb-rs";
-        let result = self.mw.upload(filename, &token, file, Some(text), Some(comment), ignorewarnings);
-
-        if let Ok(v) = result {
-            // print!("{}", v);
-            if v.get("errors").is_none() {
-                let upload = v.get("upload").unwrap();
-                let response = upload.get("result").unwrap().as_str().unwrap();
-                let filekey = &upload["filekey"].as_str();
-                match response {
-                    "Warning" => {
-                        let warnings = &upload["warnings"];
-                        let map = warnings.as_object().unwrap();
-                        let reupload = map.get("exists").and_then(|v| v.as_str()).map(|s| s == filename.replace(" ", "_")).unwrap_or(false);
-                        if map.len() == 1 && reupload {
-                            // Warning is about the page already existing, but we are updating it.
-                            self.upload_tilesheet(filename, Upload::Filekey(filekey.unwrap()), token, true);
-                            return;
-                        }
-                        println!("The API returned warnings when attempting to upload the file.");
-                        println!("Warnings: {}", serde_json::to_string(warnings).unwrap());
-                        println!("Would you like to try to upload the file again and ignore these warnings? y/n");
-                        let mut input = String::new();
-                        stdin().read_line(&mut input).unwrap();
-                        input = input.trim().to_owned();
-                        if input.to_ascii_lowercase() == "y" {
-                            self.upload_tilesheet(filename, Upload::Filekey(filekey.unwrap()), token, true);
-                        } else {
-                            println!("Please manually upload {}", filename);
+        println!("Assembling animated tilesheet layers.");
+        for tilesheet in &self.tilesheets {
+            let size = tilesheet.size;
+            let entries = match self.animations.get(&size) {
+                Some(entries) if !entries.is_empty() => entries,
+                _ => continue,
+            };
+            let mut by_z: HashMap<u32, Vec<&(TilePos, Vec<(RgbaImage, u32)>)>> = HashMap::new();
+            for entry in entries {
+                by_z.entry(entry.0.z).or_insert_with(Vec::new).push(entry);
+            }
+            for (z, mut tiles) in by_z {
+                let base = match tilesheet.layers.get(z as usize) {
+                    Some(layer) => layer,
+                    None => continue,
+                };
+                // Sort so the hash below (and therefore whether we skip re-uploading) doesn't
+                // depend on the arbitrary order tiles were packed in this run.
+                tiles.sort_by_key(|(pos, _)| (pos.x, pos.y));
+                let key = anim_manifest_key(size, z);
+                let hash = hash_animation(&tiles);
+                if !self.force && self.manifest.get(&key) == Some(&hash) {
+                    println!("Skipping unchanged animated layer {} {} {}", self.name, size, z);
+                    continue;
+                }
+                let frame_count = tiles.iter().map(|(_, frames)| frames.len()).max().unwrap_or(1);
+                let mut gif = Vec::new();
+                {
+                    let mut encoder = Encoder::new(&mut gif, base.width() as u16, base.height() as u16, &[]).unwrap();
+                    for i in 0..frame_count {
+                        let mut canvas = base.clone();
+                        // Every animated tile contributing to this GIF frame may run at its own
+                        // speed, so the frame's delay is the slowest of them rather than one
+                        // value collapsed across the whole layer.
+                        let mut delay = DEFAULT_FRAMETIME;
+                        for (pos, frames) in &tiles {
+                            let (cell, time) = &frames[i % frames.len()];
+                            blit(&mut canvas, *pos, size, cell);
+                            delay = delay.max(*time);
                         }
+                        let (width, height) = canvas.dimensions();
+                        let mut frame = Frame::from_rgba_speed(width, height, &mut canvas.into_raw(), 30);
+                        frame.delay = Delay::from_numer_denom_ms(delay * 50, 1);
+                        encoder.write_frame(&frame).unwrap();
                     }
-                    "Success" => {
-                        println!("Successfully uploaded {}", filename);
-                    }
-                    other => panic!("Unknown result: {}", other),
                 }
-            } else {
-                println!("An error occurred when uploading \"{}\". Please manually upload the file.", filename);
-                let errors = v.get("errors").unwrap().as_array();
-                if let Some(vec) = errors {
-                    let mut count = 1;
-                    for error in vec {
-                        let code = error["code"].as_str().unwrap_or("");
-                        let description = error["*"].as_str().unwrap_or("");
-                        println!("Error response from API ({}): {} - {}", count, code, description);
-                        count += 1;
-                    }
-                } else {
-                    println!("The API didn't return any error objects to display.");
+                if let Err(e) = self.store.put_animation(&self.name, size, z, "gif", &gif) {
+                    println!("ERROR: Failed to upload animation {} {} {}: {:?}", self.name, size, z, e);
+                    continue;
                 }
+                self.manifest.insert(key, hash);
             }
-        } else {
-            println!("An error occurred when uploading \"{}\". Please manually upload the file.", filename);
-            println!("Error locally: {:?}", result);
         }
+        self.animations.clear();
+        save_manifest(&self.name, &self.manifest);
+    }
+    fn upload_sheets(&mut self) {
+        for (size, z, path) in &self.paths {
+            let png = fs::read(path).unwrap();
+            if let Err(e) = self.store.put_layer(&self.name, *size, *z, &png) {
+                println!("ERROR: Failed to upload layer {} {} {}: {:?}", self.name, size, z, e);
+                continue;
+            }
+            if let Some(hash) = self.pending_hashes.remove(&manifest_key(*size, *z)) {
+                self.manifest.insert(manifest_key(*size, *z), hash);
+            }
+        }
+        save_manifest(&self.name, &self.manifest);
     }
     fn delete_tiles(&self) {
         println!("Deleting old tiles that are no longer needed.");
-        let token = self.mw.get_token().unwrap();
-        for chunk in self.deleted.chunks(50) {
-            let tiles = chunk.iter().map(|id| id.to_string()).collect::<Vec<_>>().join("|");
-            if let Err(e) = self.mw.delete_tiles(&token, &tiles) {
-                println!("ERROR: {:?}", e);
-            }
+        if let Err(e) = self.store.delete_tiles(&self.name, &self.deleted) {
+            println!("ERROR: {:?}", e);
         }
     }
     fn add_tiles(&self) {
         println!("Adding new tiles.");
-        let token = self.mw.get_token().unwrap();
-        for chunk in self.added.chunks(50) {
-            let tiles = chunk
-                .iter()
-                .map(|name| {
-                    let tile = &self.tiles[name];
-                    format!("{} {} {} {}", tile.pos.x, tile.pos.y, tile.pos.z, name)
-                })
-                .collect::<Vec<_>>()
-                .join("|");
-            if let Err(e) = self.mw.add_tiles(&token, &self.name, &tiles) {
-                println!("ERROR: {:?}", e);
+        let tiles: Vec<_> = self.added.iter().map(|name| (self.tiles[name].pos, name.clone())).collect();
+        if let Err(e) = self.store.put_tile_metadata(&self.name, &tiles) {
+            println!("ERROR: {:?}", e);
+        }
+    }
+}
+
+/// Default Hamming distance at or below which two dHashes are considered the same tile.
+const DEFAULT_DUP_THRESHOLD: u32 = 2;
+
+/// Computes a 64-bit difference-hash (dHash) for a single-frame image: grayscale, resize to 9x8,
+/// then one bit per row for each of the 8 adjacent horizontal pixel pairs (left brighter = 1).
+fn dhash(img: &RgbaImage) -> u64 {
+    let img = image::DynamicImage::ImageRgba8(img.clone()).to_luma();
+    let img = image::imageops::resize(&img, 9, 8, image::imageops::FilterType::Triangle);
+    let mut hash = 0u64;
+    for y in 0..8 {
+        for x in 0..8 {
+            let left = img.get_pixel(x, y)[0];
+            let right = img.get_pixel(x + 1, y)[0];
+            hash = (hash << 1) | (left > right) as u64;
+        }
+    }
+    hash
+}
+
+fn hamming_distance(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+/// Index into the (mcmeta-ordered) frame list used as the static tilesheet's representative
+/// frame for an animated tile.
+const DEFAULT_ANIM_FRAME: u32 = 0;
+
+/// Minecraft's default frame duration, in ticks (1 tick = 1/20s), when `.mcmeta` omits
+/// `animation.frametime` or a frame entry doesn't override it.
+const DEFAULT_FRAMETIME: u32 = 1;
+
+/// Slices a Minecraft animation strip (`height` a multiple of `width`) into `height / width`
+/// square frames, top to bottom.
+fn split_frames(img: &RgbaImage) -> Vec<RgbaImage> {
+    let (width, height) = img.dimensions();
+    (0..height / width).map(|i| image::imageops::crop_imm(img, 0, i * width, width, width).to_image()).collect()
+}
+
+/// Loads and parses an adjacent `<file>.mcmeta` for a source PNG at `png_path`, if present.
+fn load_mcmeta(png_path: &Path) -> Option<serde_json::Value> {
+    let mut mcmeta_path = png_path.as_os_str().to_owned();
+    mcmeta_path.push(".mcmeta");
+    let data = fs::read_to_string(PathBuf::from(mcmeta_path)).ok()?;
+    serde_json::from_str(&data).ok()
+}
+
+/// Resolves the playback order of an animation strip as `(frame index into the strip, duration in
+/// ticks)` pairs, honoring `animation.frames`/`frametime` when `mcmeta` is present and falling
+/// back to every strip frame in order at [`DEFAULT_FRAMETIME`] when it's absent.
+/// Mods ship `.mcmeta` files of wildly varying quality, so this always returns a non-empty,
+/// in-bounds order: entries referencing a frame index `>= frame_count` (e.g. a stale `.mcmeta`
+/// left over after the strip was trimmed) are dropped with a warning, and an empty or absent
+/// `animation.frames` falls back to every strip frame in order at `animation.frametime` (or
+/// [`DEFAULT_FRAMETIME`] when that is absent too).
+fn mcmeta_frame_order(mcmeta: Option<&serde_json::Value>, frame_count: u32) -> Vec<(u32, u32)> {
+    let animation = mcmeta.and_then(|v| v.get("animation"));
+    let default_frametime = animation.and_then(|a| a["frametime"].as_u64()).map_or(DEFAULT_FRAMETIME, |t| t as u32);
+    let order: Vec<(u32, u32)> = match animation.and_then(|a| a.get("frames")).and_then(|f| f.as_array()) {
+        Some(frames) => frames
+            .iter()
+            .filter_map(|frame| {
+                let (index, time) = match frame.as_u64() {
+                    Some(index) => (index as u32, default_frametime),
+                    None => match frame["index"].as_u64() {
+                        Some(index) => (index as u32, frame.get("time").and_then(|t| t.as_u64()).map_or(default_frametime, |t| t as u32)),
+                        None => {
+                            println!("WARNING: .mcmeta frame entry {} has no numeric \"index\"; skipping", frame);
+                            return None;
+                        }
+                    },
+                };
+                if index >= frame_count {
+                    println!("WARNING: .mcmeta frame index {} is out of range for a {}-frame strip; skipping", index, frame_count);
+                    None
+                } else {
+                    Some((index, time))
+                }
+            })
+            .collect(),
+        None => Vec::new(),
+    };
+    if order.is_empty() {
+        (0..frame_count).map(|i| (i, default_frametime)).collect()
+    } else {
+        order
+    }
+}
+
+/// Inline image protocols `preview_added_tiles` knows how to speak, in the priority order we
+/// probe them, mirroring how yazi's image adaptor picks between Kitty, iTerm2, and Sixel.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum GraphicsProtocol {
+    Kitty,
+    Iterm2,
+    Sixel,
+    None,
+}
+
+fn detect_graphics_protocol() -> GraphicsProtocol {
+    let term = env::var("TERM").unwrap_or_default();
+    let term_program = env::var("TERM_PROGRAM").unwrap_or_default();
+    if env::var_os("KITTY_WINDOW_ID").is_some() || term.contains("kitty") {
+        GraphicsProtocol::Kitty
+    } else if term_program == "iTerm.app" || term_program == "WezTerm" || env::var_os("ITERM_SESSION_ID").is_some() {
+        GraphicsProtocol::Iterm2
+    } else if term.contains("sixel") || env::var("COLORTERM").map_or(false, |c| c.contains("sixel")) {
+        GraphicsProtocol::Sixel
+    } else {
+        GraphicsProtocol::None
+    }
+}
+
+/// Raw `(columns, rows, pixel width, pixel height)` of the controlling terminal, via `TIOCGWINSZ`.
+fn terminal_window_size() -> Option<(u16, u16, u16, u16)> {
+    #[repr(C)]
+    struct WinSize {
+        ws_row: u16,
+        ws_col: u16,
+        ws_xpixel: u16,
+        ws_ypixel: u16,
+    }
+    let mut size = WinSize { ws_row: 0, ws_col: 0, ws_xpixel: 0, ws_ypixel: 0 };
+    let ret = unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) };
+    if ret == 0 && size.ws_col > 0 {
+        Some((size.ws_col, size.ws_row, size.ws_xpixel, size.ws_ypixel))
+    } else {
+        None
+    }
+}
+
+fn terminal_columns() -> Option<u16> {
+    terminal_window_size().map(|(cols, ..)| cols)
+}
+
+/// Approximate pixel size of a single terminal cell, or `None` when the terminal doesn't report
+/// its pixel dimensions over `TIOCGWINSZ`.
+fn terminal_cell_pixels() -> Option<(u16, u16)> {
+    terminal_window_size().and_then(|(cols, rows, xpx, ypx)| {
+        if xpx > 0 && ypx > 0 && cols > 0 && rows > 0 {
+            Some((xpx / cols, ypx / rows))
+        } else {
+            None
+        }
+    })
+}
+
+fn encode_png(img: &RgbaImage) -> Vec<u8> {
+    let mut png = Vec::new();
+    image::png::PNGEncoder::new(&mut png).encode(img, img.width(), img.height(), image::ColorType::RGBA(8)).unwrap();
+    png
+}
+
+/// Encodes a thumbnail as a Kitty terminal graphics protocol APC sequence, chunking the base64
+/// payload at 4096 bytes per the spec.
+fn encode_kitty(img: &RgbaImage) -> String {
+    let data = base64::encode(&encode_png(img));
+    let chunks: Vec<&[u8]> = data.as_bytes().chunks(4096).collect();
+    let mut out = String::new();
+    for (i, chunk) in chunks.iter().enumerate() {
+        let more = if i + 1 < chunks.len() { 1 } else { 0 };
+        let control = if i == 0 { format!("a=T,f=100,m={}", more) } else { format!("m={}", more) };
+        out.push_str(&format!("\x1b_G{};{}\x1b\\", control, std::str::from_utf8(chunk).unwrap()));
+    }
+    out
+}
+
+/// Encodes a thumbnail as an iTerm2 inline image OSC 1337 sequence.
+fn encode_iterm2(img: &RgbaImage) -> String {
+    let data = base64::encode(&encode_png(img));
+    format!("\x1b]1337;File=inline=1;width={}px;height={}px:{}\x07", img.width(), img.height(), data)
+}
+
+/// Encodes a thumbnail as a Sixel sequence, quantized onto a 6x6x6 RGB color cube so the palette
+/// declarations stay simple.
+fn encode_sixel(img: &RgbaImage) -> String {
+    let (w, h) = img.dimensions();
+    let to_cube = |c: u8| c as u32 * 5 / 255;
+    let color_index = |x: u32, y: u32| -> u32 {
+        let pix = img.get_pixel(x, y);
+        to_cube(pix[0]) * 36 + to_cube(pix[1]) * 6 + to_cube(pix[2])
+    };
+    let mut out = String::from("\x1bPq");
+    for i in 0..216u32 {
+        let (r, g, b) = (i / 36 % 6, i / 6 % 6, i % 6);
+        out.push_str(&format!("#{};2;{};{};{}", i, r * 100 / 5, g * 100 / 5, b * 100 / 5));
+    }
+    for band_y in (0..h).step_by(6) {
+        let band_h = 6.min(h - band_y);
+        for color in 0..216u32 {
+            let mut row = String::new();
+            let mut used = false;
+            for x in 0..w {
+                let mut bits = 0u8;
+                for dy in 0..band_h {
+                    if color_index(x, band_y + dy) == color {
+                        bits |= 1 << dy;
+                        used = true;
+                    }
+                }
+                row.push((63 + bits) as char);
+            }
+            if used {
+                out.push_str(&format!("#{}{}$", color, row));
             }
         }
+        out.push('-');
     }
+    out.push_str("\x1b\\");
+    out
 }
 
 fn load_renames(name: &str) -> HashMap<String, String> {
@@ -445,16 +1199,79 @@ fn load_renames(name: &str) -> HashMap<String, String> {
     }
 }
 
+fn manifest_key(size: u32, z: u32) -> String {
+    format!("{} {}", size, z)
+}
+
+/// Manifest key for a layer's animated GIF artifact, distinct from its static layer's key so
+/// `upload_animations` can gate uploads independently of `optimize`'s static-layer hashes.
+fn anim_manifest_key(size: u32, z: u32) -> String {
+    format!("{} anim", manifest_key(size, z))
+}
+
+/// Hashes a layer's animated tiles (already sorted into a stable order by the caller) so
+/// `upload_animations` can skip re-assembling and re-uploading a GIF whose contents didn't change.
+fn hash_animation(tiles: &[&(TilePos, Vec<(RgbaImage, u32)>)]) -> String {
+    let mut hasher = Sha256::new();
+    for (pos, frames) in tiles {
+        hasher.update(pos.x.to_le_bytes());
+        hasher.update(pos.y.to_le_bytes());
+        for (cell, time) in frames {
+            hasher.update(cell.as_raw());
+            hasher.update(time.to_le_bytes());
+        }
+    }
+    format!("{:x}", hasher.finalize())
+}
+
+fn manifest_path(name: &str) -> PathBuf {
+    Path::new(r"work/tilesheets").join(name).join("manifest.json")
+}
+
+fn load_manifest(name: &str) -> HashMap<String, String> {
+    match fs::read_to_string(manifest_path(name)) {
+        Ok(data) => serde_json::from_str(&data).unwrap(),
+        Err(_) => HashMap::new(),
+    }
+}
+
+fn save_manifest(name: &str, manifest: &HashMap<String, String>) {
+    fs::write(manifest_path(name), serde_json::to_string_pretty(manifest).unwrap()).unwrap();
+}
+
+/// Default worker pool size for packing and optimizing, when not overridden.
+fn default_jobs() -> usize {
+    thread::available_parallelism().map(|n| n.get()).unwrap_or(4)
+}
+
 pub fn update_tilesheet(name: &str) {
-    let mut manager = TilesheetManager::new(name);
+    update_tilesheet_opts(name, false, DEFAULT_DUP_THRESHOLD, default_jobs())
+}
+
+pub fn update_tilesheet_force(name: &str) {
+    update_tilesheet_opts(name, true, DEFAULT_DUP_THRESHOLD, default_jobs())
+}
+
+/// Same as [`update_tilesheet`]/[`update_tilesheet_force`], but against the wiki with the dHash
+/// duplicate threshold and worker pool size also overridable, instead of hardcoding the defaults.
+pub fn update_tilesheet_opts(name: &str, force: bool, dup_threshold: u32, jobs: usize) {
+    update_tilesheet_with_store(name, MediaWikiStore::login_path("ftb.json"), force, dup_threshold, jobs)
+}
+
+/// Runs the full check/pack/optimize/upload pipeline for `name` against an arbitrary [`TilesheetStore`],
+/// so the same pipeline can target a wiki, a CDN bucket, or a local mirror by swapping `store`.
+pub fn update_tilesheet_with_store<S: TilesheetStore>(name: &str, store: S, force: bool, dup_threshold: u32, jobs: usize) {
+    let mut manager = TilesheetManager::new(name, store, force, dup_threshold, jobs);
     manager.import_tilesheets();
     manager.import_tiles();
+    manager.dedup_duplicates();
     manager.check_changes();
     manager.confirm_changes();
     manager.record_deletions();
     manager.update();
     manager.optimize();
     manager.upload_sheets();
+    manager.upload_animations();
     manager.delete_tiles();
     manager.add_tiles();
     println!("Done");